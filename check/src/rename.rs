@@ -1,4 +1,6 @@
 use std::fmt;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 use base::ast;
 use base::ast::{Typed, DisplayEnv, MutVisitor};
@@ -10,36 +12,95 @@ use base::error::Errors;
 
 pub type Error = Errors<ast::Spanned<RenameError>>;
 
+/// Monotonically increasing counter used to mint a globally unique fresh id for each bound
+/// variable (see `stack_var`). A counter scoped to a single `rename`/`rename_with_resolution`
+/// call would reset to 0 every time, so two unrelated calls against the same symbol table could
+/// mint the same fresh name and collide once interned; a process-wide atomic sidesteps that
+/// without requiring any new state on `SymbolModule`.
+static FRESH_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A candidate binding considered (and rejected) while resolving an overloaded identifier.
+/// `span` is `None` for candidates that come from outside the module being renamed (eg.
+/// imports), where no binding-site location is available.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub symbol: Symbol,
+    pub typ: TcType,
+    pub span: Option<ast::Span>,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum RenameError {
     NoMatchingType {
         symbol: String,
         expected: TcType,
-        possible_types: Vec<TcType>,
+        candidates: Vec<Candidate>,
     },
+    UnknownField(String),
+    DuplicateField(String),
+}
+
+/// The symbol, type and (when known) definition span that an identifier occurrence resolved to.
+/// `definition` is `None` when the binding comes from outside the module being renamed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolution {
+    pub symbol: Symbol,
+    pub typ: TcType,
+    pub definition: Option<ast::Span>,
 }
 
+/// Maps an identifier occurrence to the binding it resolved to. Produced by
+/// `rename_with_resolution`.
+///
+/// Keyed by `(span, field)` rather than just `span`: punned record-field shorthand (`{ x, y }`)
+/// resolves every field at the span of the whole `Record` expression (no per-field span is
+/// available), so `field` disambiguates fields that would otherwise collide on that shared span.
+/// It is `None` for every other kind of occurrence, where the span alone is already unique.
+pub type ResolutionMap = HashMap<(ast::Span, Option<Symbol>), Resolution>;
+
+// This crate has no access to the original source text (only spans), so it cannot render an
+// annotated snippet itself (a primary label under the use site, secondary labels under each
+// candidate's definition, the way modern Rust front-ends present a diagnostic). What it can do,
+// and does here, is carry the structured data such a front-end would need: the primary span
+// (via `Errors<Spanned<RenameError>>`) and, per candidate, its span and type. A source-aware
+// front-end can turn `NoMatchingType` into a real annotated rendering; this `Display` impl is
+// only the plain-text fallback for contexts (eg. a plain stderr dump) that don't have one.
 impl fmt::Display for RenameError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            RenameError::NoMatchingType { ref symbol, ref expected, ref possible_types } => {
+            RenameError::NoMatchingType { ref symbol, ref expected, ref candidates } => {
                 try!(writeln!(f,
-                              "Could not resolve a binding for `{}` with type `{}`",
+                              "No unique binding of `{}` matches the expected type `{}`",
                               symbol,
                               expected));
-                try!(writeln!(f, "Possibilities:"));
-                for typ in possible_types {
-                    try!(writeln!(f, "{}", typ));
+                for candidate in candidates {
+                    match candidate.span {
+                        Some(ref span) => {
+                            try!(writeln!(f,
+                                          "candidate defined at {} has type `{}`",
+                                          span,
+                                          candidate.typ))
+                        }
+                        None => {
+                            try!(writeln!(f, "candidate `{}` has type `{}`", candidate.symbol, candidate.typ))
+                        }
+                    }
                 }
                 Ok(())
             }
+            RenameError::UnknownField(ref field) => {
+                write!(f, "Record has no field `{}`", field)
+            }
+            RenameError::DuplicateField(ref field) => {
+                write!(f, "Field `{}` is specified more than once", field)
+            }
         }
     }
 }
 
 struct Environment<'b> {
     env: &'b TypeEnv,
-    stack: ScopedMap<Symbol, (Symbol, TcType)>,
+    stack: ScopedMap<Symbol, (Symbol, TcType, ast::Span)>,
     stack_types: ScopedMap<Symbol, types::Alias<Symbol, TcType>>,
 }
 
@@ -67,11 +128,33 @@ pub fn rename(symbols: &mut SymbolModule,
               env: &TypeEnv,
               expr: &mut ast::LExpr<TcIdent>)
               -> Result<(), Error> {
+    rename_(symbols, env, expr, None).map(|_| ())
+}
+
+/// Like `rename`, but also returns a table mapping the span of every identifier occurrence to
+/// the symbol, type and (when known) definition span it resolved to. Editor tooling such as
+/// go-to-definition, hover types and find-all-references can be built directly on top of this
+/// without having to re-run resolution themselves.
+pub fn rename_with_resolution(symbols: &mut SymbolModule,
+                               env: &TypeEnv,
+                               expr: &mut ast::LExpr<TcIdent>)
+                               -> Result<ResolutionMap, Error> {
+    rename_(symbols, env, expr, Some(HashMap::new())).map(|resolution| resolution.unwrap())
+}
+
+fn rename_(symbols: &mut SymbolModule,
+           env: &TypeEnv,
+           expr: &mut ast::LExpr<TcIdent>,
+           resolution: Option<ResolutionMap>)
+           -> Result<Option<ResolutionMap>, Error> {
     struct RenameVisitor<'a: 'b, 'b> {
         symbols: &'b mut SymbolModule<'a>,
         env: Environment<'b>,
         inst: Instantiator,
         errors: Error,
+        // `Some` only when the caller asked for a resolution table (`rename_with_resolution`);
+        // kept as a plain `Option` so `rename` pays no bookkeeping cost.
+        resolution: Option<ResolutionMap>,
     }
     impl<'a, 'b> RenameVisitor<'a, 'b> {
         fn find_fields(&self, typ: &TcType) -> Option<Vec<types::Field<Symbol, TcType>>> {
@@ -86,18 +169,37 @@ pub fn rename(symbols: &mut SymbolModule,
             AliasInstantiator::new(&self.inst, &self.env).remove_aliases(typ.clone())
         }
 
-        fn new_pattern(&mut self, typ: &TcType, pattern: &mut ast::LPattern<TcIdent>) {
+        fn new_pattern(&mut self,
+                       typ: &TcType,
+                       pattern: &mut ast::LPattern<TcIdent>)
+                       -> Result<(), RenameError> {
+            let span = pattern.span(&ast::TcIdentEnvWrapper(&self.symbols));
             match pattern.value {
                 ast::Pattern::Record { ref mut fields, ref types, .. } => {
                     let field_types = self.find_fields(typ).expect("field_types");
+                    let mut seen = HashSet::new();
+                    // Each destructured field is bound at `span`, the whole `{ ... }` pattern's
+                    // location, rather than the individual field's own: `fields` here is a plain
+                    // `(Symbol, Option<Symbol>)` list with no per-field span to use instead. That
+                    // makes `Candidate`/`Resolution` definitions for record-pattern bindings
+                    // point at the enclosing pattern rather than the specific field, just as
+                    // `Expr::Lambda` and recursive `Expr::Let` arguments are bound at the span of
+                    // the enclosing expression.
                     for field in fields.iter_mut() {
-                        let field_type = field_types.iter()
-                            .find(|field_type| field_type.name.name_eq(&field.0))
-                            .expect("ICE: Existing field")
-                            .typ
-                            .clone();
+                        if !seen.insert(field.0.clone()) {
+                            return Err(RenameError::DuplicateField(String::from(self.symbols
+                                .string(&field.0))));
+                        }
+                        let field_type = match field_types.iter()
+                            .find(|field_type| field_type.name.name_eq(&field.0)) {
+                            Some(field_type) => field_type.typ.clone(),
+                            None => {
+                                return Err(RenameError::UnknownField(String::from(self.symbols
+                                    .string(&field.0))))
+                            }
+                        };
                         let id = field.1.as_ref().unwrap_or_else(|| &field.0).clone();
-                        field.1 = Some(self.stack_var(id, pattern.location, field_type));
+                        field.1 = Some(self.stack_var(id, span.clone(), field_type));
                     }
                     let record_type = self.remove_aliases(typ).clone();
                     let imported_types = match *record_type {
@@ -108,12 +210,11 @@ pub fn rename(symbols: &mut SymbolModule,
                         let field_type = imported_types.iter()
                             .find(|field| field.name.name_eq(name))
                             .expect("field_type");
-                        self.stack_type(name.clone(), &field_type.typ);
+                        self.stack_type(name.clone(), &field_type.typ, span.clone());
                     }
                 }
                 ast::Pattern::Identifier(ref mut id) => {
-                    let new_name =
-                        self.stack_var(id.name.clone(), pattern.location, id.typ.clone());
+                    let new_name = self.stack_var(id.name.clone(), span, id.typ.clone());
                     id.name = new_name;
                 }
                 ast::Pattern::Constructor(ref mut id, ref mut args) => {
@@ -122,32 +223,33 @@ pub fn rename(symbols: &mut SymbolModule,
                         .expect("ICE: Expected constructor")
                         .clone();
                     for (arg_type, arg) in types::arg_iter(&typ).zip(args) {
-                        arg.name =
-                            self.stack_var(arg.name.clone(), pattern.location, arg_type.clone());
+                        arg.name = self.stack_var(arg.name.clone(), span.clone(), arg_type.clone());
                     }
                 }
             }
+            Ok(())
         }
 
-        fn stack_var(&mut self, id: Symbol, location: ast::Location, typ: TcType) -> Symbol {
+        fn stack_var(&mut self, id: Symbol, span: ast::Span, typ: TcType) -> Symbol {
             let old_id = id.clone();
             let name = self.symbols.string(&id).to_owned();
-            let new_id = self.symbols.symbol(format!("{}:{}", name, location));
+            let fresh = FRESH_ID.fetch_add(1, Ordering::Relaxed);
+            let new_id = self.symbols.symbol(format!("{}${}", name, fresh));
             debug!("Rename binding `{}` = `{}` `{}`",
                    self.symbols.string(&old_id),
                    self.symbols.string(&new_id),
                    types::display_type(&self.symbols, &typ));
-            self.env.stack.insert(old_id, (new_id.clone(), typ));
+            self.env.stack.insert(old_id, (new_id.clone(), typ, span));
             new_id
 
         }
 
-        fn stack_type(&mut self, id: Symbol, alias: &Alias<Symbol, TcType>) {
+        fn stack_type(&mut self, id: Symbol, alias: &Alias<Symbol, TcType>, span: ast::Span) {
             // Insert variant constructors into the local scope
             if let Some(ref real_type) = alias.typ {
                 if let Type::Variants(ref variants) = **real_type {
                     for &(ref name, ref typ) in variants {
-                        self.env.stack.insert(name.clone(), (name.clone(), typ.clone()));
+                        self.env.stack.insert(name.clone(), (name.clone(), typ.clone(), span.clone()));
                     }
                 }
             }
@@ -157,14 +259,21 @@ pub fn rename(symbols: &mut SymbolModule,
             self.env.stack_types.insert(id, alias.clone());
         }
 
-        fn rename(&self, id: &Symbol, expected: &TcType) -> Result<Option<Symbol>, RenameError> {
+        fn rename(&self,
+                  id: &Symbol,
+                  expected: &TcType)
+                  -> Result<Option<(Symbol, TcType, Option<ast::Span>)>, RenameError> {
             let locals = self.env
                 .stack
                 .get_all(&id);
-            let global = self.env.find_type(&id).map(|typ| (id, typ));
+            let global = self.env.find_type(&id).map(|typ| (id, typ, None));
             let candidates = || {
                 locals.iter()
-                    .flat_map(|bindings| bindings.iter().rev().map(|bind| (&bind.0, &bind.1)))
+                    .flat_map(|bindings| {
+                        bindings.iter()
+                            .rev()
+                            .map(|bind| (&bind.0, &bind.1, Some(bind.2.clone())))
+                    })
                     .chain(global.clone())
             };
             // If there is a single binding (or no binding in case of primitives such as #Int+)
@@ -173,38 +282,127 @@ pub fn rename(symbols: &mut SymbolModule,
             if candidates().count() <= 1 {
                 return Ok(None);
             }
-            candidates()
-                .find(|tup| equivalent(&self.env, tup.1, expected))
-                .map(|tup| Some(tup.0.clone()))
-                .ok_or_else(|| {
-                    RenameError::NoMatchingType {
-                        symbol: String::from(self.symbols.string(id)),
-                        expected: expected.clone(),
-                        possible_types: candidates().map(|tup| tup.1.clone()).collect(),
+            // There may be more than one candidate whose type is equivalent up to a consistent
+            // renaming of quantified variables (eg. two imports of the same generic function).
+            // Only accept the match if exactly one candidate qualifies; otherwise the choice is
+            // ambiguous and we report every candidate we considered.
+            // `Environment::find_type` looks in `stack` first, so for a name with local bindings
+            // `global` only repeats the innermost one (without its span); that repeat is neither
+            // matched nor reported.
+            let considered = || candidates().filter(|tup| locals.is_none() || tup.2.is_some());
+            let mut matching = considered().filter(|tup| equivalent(&self.env, tup.1, expected));
+            let first_match = matching.next().map(|tup| (tup.0.clone(), tup.1.clone(), tup.2));
+            match first_match {
+                Some(found) => {
+                    if matching.next().is_some() {
+                        None
+                    } else {
+                        Some(found)
                     }
-                })
+                }
+                None => None,
+            }
+            .ok_or_else(|| {
+                RenameError::NoMatchingType {
+                    symbol: String::from(self.symbols.string(id)),
+                    expected: expected.clone(),
+                    candidates: considered()
+                        .map(|tup| {
+                            Candidate {
+                                symbol: tup.0.clone(),
+                                typ: tup.1.clone(),
+                                span: tup.2,
+                            }
+                        })
+                        .collect(),
+                }
+            })
+            .map(Some)
+        }
+
+        // Resolves `id` the same way `rename` does, additionally recording the occurrence at
+        // `span` into the resolution table (when one was requested). `field` disambiguates
+        // punned record-field shorthand occurrences, which otherwise all share `span` with the
+        // enclosing `Record` expression; every other caller passes `None`.
+        fn resolve(&mut self,
+                   id: &Symbol,
+                   expected: &TcType,
+                   span: ast::Span,
+                   field: Option<Symbol>)
+                   -> Result<Symbol, RenameError> {
+            match try!(self.rename(id, expected)) {
+                Some((new_id, typ, definition)) => {
+                    self.record_resolution(span, field, new_id.clone(), typ, definition);
+                    Ok(new_id)
+                }
+                None => {
+                    // No ambiguity, so `id` itself is left unrenamed (see `rename`). The
+                    // resolution table should still point at the binding's real fresh symbol
+                    // and declared type rather than the pre-rename name and call-site type,
+                    // otherwise two shadowed bindings sharing a surface name become
+                    // indistinguishable to callers such as go-to-definition.
+                    match self.env.stack.get(id) {
+                        Some(bind) => {
+                            self.record_resolution(span, field, bind.0.clone(), bind.1.clone(), Some(bind.2.clone()));
+                        }
+                        None => {
+                            self.record_resolution(span, field, id.clone(), expected.clone(), None);
+                        }
+                    }
+                    Ok(id.clone())
+                }
+            }
+        }
+
+        fn record_resolution(&mut self,
+                              span: ast::Span,
+                              field: Option<Symbol>,
+                              symbol: Symbol,
+                              typ: TcType,
+                              definition: Option<ast::Span>) {
+            if let Some(ref mut resolution) = self.resolution {
+                resolution.insert((span, field),
+                                   Resolution {
+                                       symbol: symbol,
+                                       typ: typ,
+                                       definition: definition,
+                                   });
+            }
         }
 
         fn rename_expr(&mut self, expr: &mut ast::LExpr<TcIdent>) -> Result<(), RenameError> {
+            let span = expr.span(&ast::TcIdentEnvWrapper(&self.symbols));
             match expr.value {
                 ast::Expr::Identifier(ref mut id) => {
-                    let new_id = try!(self.rename(id.id(), &id.typ));
+                    let new_name = try!(self.resolve(id.id(), &id.typ, span, None));
                     debug!("Rename identifier {} = {}",
                            self.symbols.string(&id.name),
-                           self.symbols.string(new_id.as_ref().unwrap_or(&id.name)));
-                    id.name = new_id.unwrap_or_else(|| id.name.clone());
+                           self.symbols.string(&new_name));
+                    id.name = new_name;
                 }
                 ast::Expr::Record { ref mut typ, ref mut exprs, .. } => {
                     let field_types = self.find_fields(&typ.typ).expect("field_types");
-                    for (field, &mut (ref id, ref mut maybe_expr)) in field_types.iter()
-                        .zip(exprs) {
+                    let mut seen = HashSet::new();
+                    for &mut (ref id, ref mut maybe_expr) in exprs.iter_mut() {
+                        if !seen.insert(id.clone()) {
+                            return Err(RenameError::DuplicateField(String::from(self.symbols
+                                .string(id))));
+                        }
+                        let field = match field_types.iter()
+                            .find(|field_type| field_type.name.name_eq(id)) {
+                            Some(field) => field,
+                            None => {
+                                return Err(RenameError::UnknownField(String::from(self.symbols
+                                    .string(id))))
+                            }
+                        };
                         match *maybe_expr {
                             Some(ref mut expr) => self.visit_expr(expr),
                             None => {
-                                let new_id = try!(self.rename(id, &field.typ));
+                                let new_id = try!(self.resolve(id, &field.typ, span.clone(), Some(id.clone())));
                                 *maybe_expr =
                                     Some(ast::no_loc(ast::Expr::Identifier(ast::TcIdent {
-                                        name: new_id.unwrap_or_else(|| id.clone()),
+                                        name: new_id,
                                         typ: field.typ.clone(),
                                     })));
                             }
@@ -212,11 +410,11 @@ pub fn rename(symbols: &mut SymbolModule,
                     }
                 }
                 ast::Expr::BinOp(ref mut l, ref mut id, ref mut r) => {
-                    let new_id = try!(self.rename(id.id(), &id.typ));
+                    let new_name = try!(self.resolve(id.id(), &id.typ, span, None));
                     debug!("Rename {} = {}",
                            self.symbols.string(&id.name),
-                           self.symbols.string(new_id.as_ref().unwrap_or(&id.name)));
-                    id.name = new_id.unwrap_or_else(|| id.name.clone());
+                           self.symbols.string(&new_name));
+                    id.name = new_name;
                     self.visit_expr(l);
                     self.visit_expr(r);
                 }
@@ -226,7 +424,7 @@ pub fn rename(symbols: &mut SymbolModule,
                         self.env.stack_types.enter_scope();
                         self.env.stack.enter_scope();
                         let typ = expr.env_type_of(&self.env);
-                        self.new_pattern(&typ, &mut alt.pattern);
+                        try!(self.new_pattern(&typ, &mut alt.pattern));
                         self.visit_expr(&mut alt.expression);
                         self.env.stack.exit_scope();
                         self.env.stack_types.exit_scope();
@@ -241,15 +439,14 @@ pub fn rename(symbols: &mut SymbolModule,
                             self.visit_expr(&mut bind.expression);
                         }
                         let typ = bind.env_type_of(&self.env);
-                        self.new_pattern(&typ, &mut bind.name);
+                        try!(self.new_pattern(&typ, &mut bind.name));
                     }
                     if is_recursive {
                         for bind in bindings {
                             self.env.stack.enter_scope();
                             for (typ, arg) in types::arg_iter(&bind.type_of())
                                 .zip(&mut bind.arguments) {
-                                arg.name =
-                                    self.stack_var(arg.name.clone(), expr.location, typ.clone());
+                                arg.name = self.stack_var(arg.name.clone(), span.clone(), typ.clone());
                             }
                             self.visit_expr(&mut bind.expression);
                             self.env.stack.exit_scope();
@@ -262,7 +459,7 @@ pub fn rename(symbols: &mut SymbolModule,
                 ast::Expr::Lambda(ref mut lambda) => {
                     self.env.stack.enter_scope();
                     for (typ, arg) in types::arg_iter(&lambda.id.typ).zip(&mut lambda.arguments) {
-                        arg.name = self.stack_var(arg.name.clone(), expr.location, typ.clone());
+                        arg.name = self.stack_var(arg.name.clone(), span.clone(), typ.clone());
                     }
                     self.visit_expr(&mut lambda.body);
                     self.env.stack.exit_scope();
@@ -270,7 +467,7 @@ pub fn rename(symbols: &mut SymbolModule,
                 ast::Expr::Type(ref bindings, ref mut expr) => {
                     self.env.stack_types.enter_scope();
                     for bind in bindings {
-                        self.stack_type(bind.name.clone(), &bind.alias);
+                        self.stack_type(bind.name.clone(), &bind.alias, span.clone());
                     }
                     self.visit_expr(expr);
                     self.env.stack_types.exit_scope();
@@ -296,6 +493,7 @@ pub fn rename(symbols: &mut SymbolModule,
         symbols: symbols,
         errors: Errors::new(),
         inst: Instantiator::new(),
+        resolution: resolution,
         env: Environment {
             env: env,
             stack: ScopedMap::new(),
@@ -306,12 +504,209 @@ pub fn rename(symbols: &mut SymbolModule,
     if visitor.errors.has_errors() {
         Err(visitor.errors)
     } else {
-        Ok(())
+        Ok(visitor.resolution)
+    }
+}
+
+// No #[test]s in this module: every fixture this file's public API needs (Symbol, TcType,
+// SymbolModule, LExpr<TcIdent>, ...) is defined in `base`, which isn't part of this checkout
+// (only check/src/rename.rs is present here, with no Cargo.toml to run a test binary even if it
+// were). A test module added against this tree could only guess at those types' constructors,
+// which would be worse than no tests - it would look like coverage without actually exercising
+// anything. Once this file lives in the full workspace, the tests this series is missing are:
+//   - alpha_eq: round-trip a function call, an if/else and a tuple through rename + alpha_eq
+//     (alpha_eq(e, e) must hold for ordinary expressions, not just let/lambda/match)
+//   - rename_with_resolution: a record literal with two or more punned fields, asserting every
+//     field gets its own entry in the resolution map
+//   - new_pattern's Record arm: duplicate and unknown field names, asserting the right
+//     RenameError variant
+//   - equivalent: actual `a -> a` against inferred `b -> Int` (b generic), asserting false - the
+//     skolem-bijection/map contradiction chunk0-5 originally missed
+//
+/// Compares `l` and `r` for structural equality up to a consistent renaming of bound variables.
+/// Two binders are considered the same position as long as every occurrence of the left binder
+/// lines up with the corresponding occurrence of the right binder (and vice versa); free and
+/// global symbols must match exactly. This gives a location-independent way to compare the
+/// output of `rename`, which otherwise mints fresh, non-deterministic names for every binding.
+pub fn alpha_eq(l: &ast::LExpr<TcIdent>, r: &ast::LExpr<TcIdent>) -> bool {
+    let mut checker = AlphaEq {
+        left_to_right: ScopedMap::new(),
+        right_to_left: ScopedMap::new(),
+    };
+    checker.expr(l, r)
+}
+
+struct AlphaEq {
+    left_to_right: ScopedMap<Symbol, Symbol>,
+    right_to_left: ScopedMap<Symbol, Symbol>,
+}
+
+impl AlphaEq {
+    fn bind(&mut self, l: &Symbol, r: &Symbol) {
+        self.left_to_right.insert(l.clone(), r.clone());
+        self.right_to_left.insert(r.clone(), l.clone());
+    }
+
+    fn symbols_eq(&self, l: &Symbol, r: &Symbol) -> bool {
+        match self.left_to_right.get(l) {
+            Some(bound_r) => bound_r == r,
+            None => self.right_to_left.get(r).is_none() && l == r,
+        }
+    }
+
+    fn pattern(&mut self, l: &ast::LPattern<TcIdent>, r: &ast::LPattern<TcIdent>) -> bool {
+        match (&l.value, &r.value) {
+            (&ast::Pattern::Identifier(ref l_id), &ast::Pattern::Identifier(ref r_id)) => {
+                self.bind(l_id.id(), r_id.id());
+                true
+            }
+            (&ast::Pattern::Constructor(ref l_id, ref l_args),
+             &ast::Pattern::Constructor(ref r_id, ref r_args)) => {
+                if l_id.id() != r_id.id() || l_args.len() != r_args.len() {
+                    return false;
+                }
+                for (l_arg, r_arg) in l_args.iter().zip(r_args) {
+                    self.bind(&l_arg.name, &r_arg.name);
+                }
+                true
+            }
+            (&ast::Pattern::Record { fields: ref l_fields, .. },
+             &ast::Pattern::Record { fields: ref r_fields, .. }) => {
+                if l_fields.len() != r_fields.len() {
+                    return false;
+                }
+                for (l_field, r_field) in l_fields.iter().zip(r_fields) {
+                    if !l_field.0.name_eq(&r_field.0) {
+                        return false;
+                    }
+                    let l_bound = l_field.1.as_ref().unwrap_or(&l_field.0);
+                    let r_bound = r_field.1.as_ref().unwrap_or(&r_field.0);
+                    self.bind(l_bound, r_bound);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expr(&mut self, l: &ast::LExpr<TcIdent>, r: &ast::LExpr<TcIdent>) -> bool {
+        match (&l.value, &r.value) {
+            (&ast::Expr::Identifier(ref l_id), &ast::Expr::Identifier(ref r_id)) => {
+                self.symbols_eq(l_id.id(), r_id.id())
+            }
+            (&ast::Expr::BinOp(ref l_l, ref l_id, ref l_r),
+             &ast::Expr::BinOp(ref r_l, ref r_id, ref r_r)) => {
+                self.symbols_eq(l_id.id(), r_id.id()) && self.expr(l_l, r_l) && self.expr(l_r, r_r)
+            }
+            (&ast::Expr::Record { exprs: ref l_exprs, .. },
+             &ast::Expr::Record { exprs: ref r_exprs, .. }) => {
+                l_exprs.len() == r_exprs.len() &&
+                l_exprs.iter().zip(r_exprs).all(|(l_field, r_field)| {
+                    l_field.0.name_eq(&r_field.0) &&
+                    match (&l_field.1, &r_field.1) {
+                        (&Some(ref l_e), &Some(ref r_e)) => self.expr(l_e, r_e),
+                        (&None, &None) => true,
+                        _ => false,
+                    }
+                })
+            }
+            (&ast::Expr::Match(ref l_expr, ref l_alts), &ast::Expr::Match(ref r_expr, ref r_alts)) => {
+                if !self.expr(l_expr, r_expr) || l_alts.len() != r_alts.len() {
+                    return false;
+                }
+                l_alts.iter().zip(r_alts).all(|(l_alt, r_alt)| {
+                    self.left_to_right.enter_scope();
+                    self.right_to_left.enter_scope();
+                    let eq = self.pattern(&l_alt.pattern, &r_alt.pattern) &&
+                             self.expr(&l_alt.expression, &r_alt.expression);
+                    self.left_to_right.exit_scope();
+                    self.right_to_left.exit_scope();
+                    eq
+                })
+            }
+            (&ast::Expr::Let(ref l_binds, ref l_expr), &ast::Expr::Let(ref r_binds, ref r_expr)) => {
+                if l_binds.len() != r_binds.len() {
+                    return false;
+                }
+                self.left_to_right.enter_scope();
+                self.right_to_left.enter_scope();
+                let mut patterns_eq = true;
+                for (l_bind, r_bind) in l_binds.iter().zip(r_binds) {
+                    patterns_eq = self.pattern(&l_bind.name, &r_bind.name) && patterns_eq;
+                }
+                let eq = patterns_eq && l_binds.iter().zip(r_binds).all(|(l_bind, r_bind)| {
+                    if l_bind.arguments.len() != r_bind.arguments.len() {
+                        return false;
+                    }
+                    self.left_to_right.enter_scope();
+                    self.right_to_left.enter_scope();
+                    for (l_arg, r_arg) in l_bind.arguments.iter().zip(&r_bind.arguments) {
+                        self.bind(&l_arg.name, &r_arg.name);
+                    }
+                    let eq = self.expr(&l_bind.expression, &r_bind.expression);
+                    self.left_to_right.exit_scope();
+                    self.right_to_left.exit_scope();
+                    eq
+                }) && self.expr(l_expr, r_expr);
+                self.left_to_right.exit_scope();
+                self.right_to_left.exit_scope();
+                eq
+            }
+            (&ast::Expr::Lambda(ref l_lambda), &ast::Expr::Lambda(ref r_lambda)) => {
+                if l_lambda.arguments.len() != r_lambda.arguments.len() {
+                    return false;
+                }
+                self.left_to_right.enter_scope();
+                self.right_to_left.enter_scope();
+                for (l_arg, r_arg) in l_lambda.arguments.iter().zip(&r_lambda.arguments) {
+                    self.bind(&l_arg.name, &r_arg.name);
+                }
+                let eq = self.expr(&l_lambda.body, &r_lambda.body);
+                self.left_to_right.exit_scope();
+                self.right_to_left.exit_scope();
+                eq
+            }
+            (&ast::Expr::Type(ref l_binds, ref l_expr), &ast::Expr::Type(ref r_binds, ref r_expr)) => {
+                l_binds.len() == r_binds.len() &&
+                l_binds.iter().zip(r_binds).all(|(l_bind, r_bind)| l_bind.name.name_eq(&r_bind.name)) &&
+                self.expr(l_expr, r_expr)
+            }
+            (&ast::Expr::Literal(ref l_lit), &ast::Expr::Literal(ref r_lit)) => l_lit == r_lit,
+            (&ast::Expr::Call(ref l_func, ref l_args), &ast::Expr::Call(ref r_func, ref r_args)) => {
+                self.expr(l_func, r_func) && l_args.len() == r_args.len() &&
+                l_args.iter().zip(r_args).all(|(l_arg, r_arg)| self.expr(l_arg, r_arg))
+            }
+            (&ast::Expr::IfElse(ref l_pred, ref l_if_true, ref l_if_false),
+             &ast::Expr::IfElse(ref r_pred, ref r_if_true, ref r_if_false)) => {
+                self.expr(l_pred, r_pred) && self.expr(l_if_true, r_if_true) &&
+                match (l_if_false, r_if_false) {
+                    (&Some(ref l), &Some(ref r)) => self.expr(l, r),
+                    (&None, &None) => true,
+                    _ => false,
+                }
+            }
+            (&ast::Expr::Block(ref l_exprs), &ast::Expr::Block(ref r_exprs)) => {
+                l_exprs.len() == r_exprs.len() &&
+                l_exprs.iter().zip(r_exprs).all(|(l_e, r_e)| self.expr(l_e, r_e))
+            }
+            (&ast::Expr::Tuple(ref l_exprs), &ast::Expr::Tuple(ref r_exprs)) => {
+                l_exprs.len() == r_exprs.len() &&
+                l_exprs.iter().zip(r_exprs).all(|(l_e, r_e)| self.expr(l_e, r_e))
+            }
+            (&ast::Expr::Array(ref l_exprs), &ast::Expr::Array(ref r_exprs)) => {
+                l_exprs.len() == r_exprs.len() &&
+                l_exprs.iter().zip(r_exprs).all(|(l_e, r_e)| self.expr(l_e, r_e))
+            }
+            (&ast::Expr::FieldAccess(ref l_expr, ref l_field),
+             &ast::Expr::FieldAccess(ref r_expr, ref r_field)) => {
+                self.expr(l_expr, r_expr) && l_field.id().name_eq(r_field.id())
+            }
+            _ => false,
+        }
     }
 }
 
 
-use std::collections::HashMap;
 use base::instantiate::{Instantiator, AliasInstantiator};
 use unify_type::TypeError;
 use substitution::Substitution;
@@ -322,6 +717,8 @@ pub fn equivalent(env: &TypeEnv, actual: &TcType, inferred: &TcType) -> bool {
     let subs = Substitution::new();
     let mut state = AliasInstantiator::new(&inst, env);
     let mut map = HashMap::new();
+    let mut skolem_left_to_right = HashMap::new();
+    let mut skolem_right_to_left = HashMap::new();
     let mut equiv = true;
     {
         let mut unifier = UnifierState {
@@ -329,6 +726,8 @@ pub fn equivalent(env: &TypeEnv, actual: &TcType, inferred: &TcType) -> bool {
             subs: &subs,
             unifier: Equivalent {
                 map: &mut map,
+                skolem_left_to_right: &mut skolem_left_to_right,
+                skolem_right_to_left: &mut skolem_right_to_left,
                 equiv: &mut equiv,
             },
         };
@@ -339,6 +738,12 @@ pub fn equivalent(env: &TypeEnv, actual: &TcType, inferred: &TcType) -> bool {
 
 struct Equivalent<'m> {
     map: &'m mut HashMap<Symbol, TcType>,
+    // Bijection between quantified variables seen on the left and right side of the match.
+    // Lets two differently-numbered (but consistently occurring) generics compare equal without
+    // requiring their raw ids to coincide, as if each had been skolemized to a fresh rigid
+    // constant paired one-to-one with its counterpart on the other side.
+    skolem_left_to_right: &'m mut HashMap<Symbol, Symbol>,
+    skolem_right_to_left: &'m mut HashMap<Symbol, Symbol>,
     equiv: &'m mut bool,
 }
 
@@ -355,8 +760,33 @@ impl<'a, 'm> Unifier<AliasInstantiator<'a>, TcType> for Equivalent<'m> {
         let l = subs.real(l);
         let r = subs.real(r);
         match (&**l, &**r) {
-            (&Type::Generic(ref gl), &Type::Generic(ref gr)) if gl == gr => None,
+            (&Type::Generic(ref gl), &Type::Generic(ref gr)) => {
+                // Both sides are rigid (skolemized) quantified variables: they are equivalent
+                // only if every occurrence of `gl` lines up with the same `gr` and vice versa.
+                // A `gl` that was already forced equal to a concrete type below (via `map`)
+                // can't also be treated as a rigid variable paired one-to-one with `gr`.
+                let consistent = !unifier.unifier.map.contains_key(&gl.id) &&
+                                  match unifier.unifier.skolem_left_to_right.get(&gl.id) {
+                    Some(paired_with) => *paired_with == gr.id,
+                    None => !unifier.unifier.skolem_right_to_left.contains_key(&gr.id),
+                };
+                if consistent {
+                    unifier.unifier.skolem_left_to_right.insert(gl.id.clone(), gr.id.clone());
+                    unifier.unifier.skolem_right_to_left.insert(gr.id.clone(), gl.id.clone());
+                } else {
+                    *unifier.unifier.equiv = false;
+                }
+                None
+            }
             (&Type::Generic(ref gl), _) => {
+                // A `gl` that was already pinned to a specific right-side generic above (via
+                // the skolem bijection) can't also be substituted for an unrelated concrete
+                // type here; without this check the two arms track contradictory information
+                // about `gl` completely independently and neither ever notices.
+                if unifier.unifier.skolem_left_to_right.contains_key(&gl.id) {
+                    *unifier.unifier.equiv = false;
+                    return None;
+                }
                 match unifier.unifier.map.get(&gl.id).cloned() {
                     Some(ref typ) => unifier.try_match(typ, r),
                     None => {
@@ -365,6 +795,12 @@ impl<'a, 'm> Unifier<AliasInstantiator<'a>, TcType> for Equivalent<'m> {
                     }
                 }
             }
+            (_, &Type::Generic(_)) => {
+                // A concrete actual type can never be an instance of a rigid quantified
+                // variable on the expected side.
+                *unifier.unifier.equiv = false;
+                None
+            }
             _ => {
                 let result = {
                     let next_unifier = UnifierState {
@@ -372,6 +808,8 @@ impl<'a, 'm> Unifier<AliasInstantiator<'a>, TcType> for Equivalent<'m> {
                         subs: subs,
                         unifier: Equivalent {
                             map: unifier.unifier.map,
+                            skolem_left_to_right: unifier.unifier.skolem_left_to_right,
+                            skolem_right_to_left: unifier.unifier.skolem_right_to_left,
                             equiv: unifier.unifier.equiv,
                         },
                     };